@@ -0,0 +1,329 @@
+// Copyright (c) 2020 Dropbox, Inc.
+
+//! An async, non-blocking HTTP client, for use from within an async runtime.
+//!
+//! This module mirrors [`crate::default_client`], but instead of making blocking calls with
+//! `ureq`, it drives requests through `reqwest` on top of `tokio`, and hands back download bodies
+//! as an [`AsyncRead`] instead of a boxed blocking [`std::io::Read`]. Use this if your program is
+//! already running inside an async runtime and you don't want to pay for a blocking thread per
+//! request.
+//!
+//! This code (and its dependencies) are only built if you use the `async` Cargo feature.
+
+use crate::Error;
+use crate::client_trait::{Endpoint, ParamsType, Style, TeamSelect};
+use crate::common::NamespaceId;
+
+use futures::io::AsyncRead;
+use std::pin::Pin;
+
+const USER_AGENT: &str = concat!("Dropbox-APIv2-Rust/", env!("CARGO_PKG_VERSION"));
+
+/// The result of a successfully-issued async HTTP request: the JSON body (or header, for
+/// downloads) plus, for downloads, a streaming body.
+pub struct AsyncHttpRequestResultRaw {
+    /// The metadata or result JSON returned by the call.
+    pub result_json: String,
+
+    /// The length of the body, if any, and if known.
+    pub content_length: Option<u64>,
+
+    /// The body of the response, streamed asynchronously, if this was a download-style request.
+    ///
+    /// This is `Send` but deliberately not `Sync`: the `reqwest`/`hyper` stream backing it isn't
+    /// `Sync`, so requiring it here would make it impossible to build this from a real response.
+    pub body: Option<Pin<Box<dyn AsyncRead + Send>>>,
+}
+
+/// The async equivalent of [`crate::client_trait::HttpClient`]. Implement this trait to plug a
+/// different async HTTP client implementation into the generated `files::*` etc. functions' async
+/// counterparts.
+#[async_trait::async_trait]
+pub trait AsyncHttpClient {
+    /// Make an HTTP request to the Dropbox API, asynchronously.
+    #[allow(clippy::too_many_arguments)]
+    async fn request(
+        &self,
+        endpoint: Endpoint,
+        style: Style,
+        function: &str,
+        params: String,
+        params_type: ParamsType,
+        body: Option<&[u8]>,
+        range_start: Option<u64>,
+        range_end: Option<u64>,
+    ) -> crate::Result<AsyncHttpRequestResultRaw>;
+}
+
+/// Marker trait for async clients doing User authorization.
+pub trait AsyncUserAuthClient: AsyncHttpClient {}
+
+/// Marker trait for async clients doing Team authorization.
+pub trait AsyncTeamAuthClient: AsyncHttpClient {}
+
+/// Marker trait for async clients making unauthenticated calls.
+pub trait AsyncNoauthClient: AsyncHttpClient {}
+
+/// The async equivalent of a generated RPC-style `files::*` function (e.g. `files::list_folder`):
+/// issues the request through [`AsyncHttpClient::request`] and hands back the raw result JSON for
+/// the caller to deserialize into the appropriate result/error type, the same way the sync
+/// generated functions deserialize what `HttpClient::request` gives them.
+pub async fn async_rpc_request<T: AsyncHttpClient>(
+    client: &T,
+    function: &str,
+    params: String,
+    params_type: ParamsType,
+) -> crate::Result<String> {
+    Ok(client.request(Endpoint::Api, Style::Rpc, function, params, params_type, None, None, None)
+        .await?
+        .result_json)
+}
+
+/// The async equivalent of a generated download-style function (e.g. `files::download`): issues
+/// the request through [`AsyncHttpClient::request`] and hands back the raw result, including the
+/// streamed body, for the caller to deserialize the result JSON from.
+pub async fn async_download_request<T: AsyncHttpClient>(
+    client: &T,
+    function: &str,
+    params: String,
+    params_type: ParamsType,
+    range_start: Option<u64>,
+    range_end: Option<u64>,
+) -> crate::Result<AsyncHttpRequestResultRaw> {
+    client.request(Endpoint::Content, Style::Download, function, params, params_type, None,
+        range_start, range_end).await
+}
+
+/// The async equivalent of a generated upload-style function (e.g. `files::upload`): issues the
+/// request through [`AsyncHttpClient::request`] with `body` as the uploaded data, and hands back
+/// the raw result JSON for the caller to deserialize.
+pub async fn async_upload_request<T: AsyncHttpClient>(
+    client: &T,
+    function: &str,
+    params: String,
+    params_type: ParamsType,
+    body: &[u8],
+) -> crate::Result<String> {
+    Ok(client.request(Endpoint::Content, Style::Upload, function, params, params_type, Some(body),
+        None, None)
+        .await?
+        .result_json)
+}
+
+macro_rules! forward_async_request {
+    ($self:ident, $inner:expr, $token:expr, $team_select:expr, $namespace_id:expr) => {
+        async fn request(
+            &$self,
+            endpoint: Endpoint,
+            style: Style,
+            function: &str,
+            params: String,
+            params_type: ParamsType,
+            body: Option<&[u8]>,
+            range_start: Option<u64>,
+            range_end: Option<u64>,
+        ) -> crate::Result<AsyncHttpRequestResultRaw> {
+            $inner.request(endpoint, style, function, params, params_type, body, range_start,
+                range_end, $token, $team_select, $namespace_id).await
+        }
+    }
+}
+
+/// Default async HTTP client using User authorization.
+pub struct UserAuthDefaultAsyncClient {
+    inner: ReqwestClient,
+    token: String,
+    namespace_id: Option<NamespaceId>,
+}
+
+impl UserAuthDefaultAsyncClient {
+    /// Create a new client using the given OAuth2 token.
+    pub fn new(token: String) -> Self {
+        Self {
+            inner: ReqwestClient::default(),
+            token,
+            namespace_id: None,
+        }
+    }
+
+    /// Set a namespace_id as the path root for future requests.
+    pub fn namespace_id(&mut self, namespace_id: Option<NamespaceId>) {
+        self.namespace_id = namespace_id;
+    }
+}
+
+#[async_trait::async_trait]
+impl AsyncHttpClient for UserAuthDefaultAsyncClient {
+    forward_async_request! { self, self.inner, Some(&self.token), None, self.namespace_id.as_ref() }
+}
+
+impl AsyncUserAuthClient for UserAuthDefaultAsyncClient {}
+
+/// Default async HTTP client using Team authorization.
+pub struct TeamAuthDefaultAsyncClient {
+    inner: ReqwestClient,
+    token: String,
+    team_select: Option<TeamSelect>,
+}
+
+impl TeamAuthDefaultAsyncClient {
+    /// Create a new client using the given OAuth2 token, with no user/admin context selected.
+    pub fn new(token: String) -> Self {
+        Self {
+            inner: ReqwestClient::default(),
+            token,
+            team_select: None,
+        }
+    }
+
+    /// Select a user or team context to operate in.
+    pub fn select(&mut self, team_select: Option<TeamSelect>) {
+        self.team_select = team_select;
+    }
+}
+
+#[async_trait::async_trait]
+impl AsyncHttpClient for TeamAuthDefaultAsyncClient {
+    forward_async_request! { self, self.inner, Some(&self.token), self.team_select.as_ref(), None }
+}
+
+impl AsyncTeamAuthClient for TeamAuthDefaultAsyncClient {}
+
+/// Default async HTTP client for unauthenticated API calls.
+#[derive(Default)]
+pub struct NoauthDefaultAsyncClient {
+    inner: ReqwestClient,
+}
+
+#[async_trait::async_trait]
+impl AsyncHttpClient for NoauthDefaultAsyncClient {
+    forward_async_request! { self, self.inner, None, None, None }
+}
+
+impl AsyncNoauthClient for NoauthDefaultAsyncClient {}
+
+#[derive(Default)]
+struct ReqwestClient {
+    client: reqwest::Client,
+}
+
+impl ReqwestClient {
+    #[allow(clippy::too_many_arguments)]
+    async fn request(
+        &self,
+        endpoint: Endpoint,
+        style: Style,
+        function: &str,
+        params: String,
+        params_type: ParamsType,
+        body: Option<&[u8]>,
+        range_start: Option<u64>,
+        range_end: Option<u64>,
+        token: Option<&str>,
+        team_select: Option<&TeamSelect>,
+        namespace_id: Option<&NamespaceId>,
+    ) -> crate::Result<AsyncHttpRequestResultRaw> {
+
+        let url = endpoint.url().to_owned() + function;
+        debug!("async request for {:?}", url);
+
+        let mut req = self.client.post(&url).header("User-Agent", USER_AGENT);
+
+        if let Some(token) = token {
+            req = req.header("Authorization", format!("Bearer {}", token));
+        }
+
+        if let Some(team_select) = team_select {
+            req = match team_select {
+                TeamSelect::User(id) => req.header("Dropbox-API-Select-User", id),
+                TeamSelect::Admin(id) => req.header("Dropbox-API-Select-Admin", id),
+            };
+        }
+
+        if let Some(namespace_id) = namespace_id {
+            let namespace_tag = format!(r#"{{".tag": "namespace_id", "namespace_id": "{}"}}"#, namespace_id);
+            req = req.header("Dropbox-API-Path-Root", namespace_tag);
+        }
+
+        req = match (range_start, range_end) {
+            (Some(start), Some(end)) => req.header("Range", format!("bytes={}-{}", start, end)),
+            (Some(start), None) => req.header("Range", format!("bytes={}-", start)),
+            (None, Some(end)) => req.header("Range", format!("bytes=-{}", end)),
+            (None, None) => req,
+        };
+
+        if !params.is_empty() {
+            req = match style {
+                Style::Rpc => {
+                    req.header("Content-Type", params_type.content_type()).body(params)
+                }
+                Style::Upload | Style::Download => {
+                    req = req.header("Dropbox-API-Arg", params);
+                    if style == Style::Upload {
+                        req = req.header("Content-Type", "application/octet-stream");
+                        req.body(body.map(|b| b.to_vec()).unwrap_or_default())
+                    } else {
+                        assert!(body.is_none(), "body can only be set for Style::Upload request");
+                        req
+                    }
+                }
+            };
+        }
+
+        let resp = req.send().await.map_err(RequestError)?;
+
+        if !resp.status().is_success() {
+            let code = resp.status().as_u16() as u32;
+            let status = resp.status().canonical_reason().unwrap_or("").to_owned();
+            let json = resp.text().await.map_err(RequestError)?;
+            return Err(Error::UnexpectedHttpError {
+                code,
+                status,
+                json,
+            });
+        }
+
+        match style {
+            Style::Rpc | Style::Upload => {
+                let result_json = resp.text().await.map_err(RequestError)?;
+                Ok(AsyncHttpRequestResultRaw {
+                    result_json,
+                    content_length: None,
+                    body: None,
+                })
+            }
+            Style::Download => {
+                let result_json = resp.headers().get("Dropbox-API-Result")
+                    .ok_or(Error::UnexpectedResponse("missing Dropbox-API-Result header"))?
+                    .to_str()
+                    .map_err(|_| Error::UnexpectedResponse("invalid Dropbox-API-Result header"))?
+                    .to_owned();
+
+                let content_length = resp.content_length();
+
+                use futures::TryStreamExt;
+                let stream = resp.bytes_stream()
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e));
+                let body = stream.into_async_read();
+
+                Ok(AsyncHttpRequestResultRaw {
+                    result_json,
+                    content_length,
+                    body: Some(Box::pin(body)),
+                })
+            }
+        }
+    }
+}
+
+/// Something went wrong making the async request, or the server returned a response we didn't
+/// expect. Use the `Display` or `Debug` impls to see more details.
+#[derive(thiserror::Error, Debug)]
+#[error("{0}")]
+pub struct RequestError(#[from] reqwest::Error);
+
+impl From<RequestError> for crate::Error {
+    fn from(e: RequestError) -> Self {
+        Self::HttpClient(Box::new(e))
+    }
+}
@@ -15,8 +15,216 @@ use crate::Error;
 use crate::client_trait::*;
 use crate::common::NamespaceId;
 
+use std::time::Duration;
+
 const USER_AGENT: &str = concat!("Dropbox-APIv2-Rust/", env!("CARGO_PKG_VERSION"));
 
+/// Configuration for the default clients' underlying HTTP connection handling: timeouts, proxy,
+/// and retries.
+///
+/// Construct one with [`ClientConfig::new`] and pass it to e.g.
+/// [`UserAuthDefaultClient::new_with_config`]. The default (from [`Default::default`]) matches
+/// what you get from the plain `new` constructors.
+///
+/// Note that in ureq 1.x (what this crate uses), timeouts and the proxy are per-request settings
+/// rather than something you configure once on the `Agent` up front, so these are applied to each
+/// outgoing request rather than baked into the `Agent` built in [`UreqClient::new`]. The `Agent`
+/// itself is still built once and reused, so connections continue to be pooled and reused across
+/// requests.
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    connect_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    proxy: Option<String>,
+    retry_policy: RetryPolicy,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            connect_timeout: None,
+            read_timeout: None,
+            proxy: None,
+            retry_policy: RetryPolicy::default(),
+        }
+    }
+}
+
+impl ClientConfig {
+    /// Create a new config with default settings.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the timeout for establishing a connection. Default is no timeout.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Set the timeout for reading a response once connected. Default is no timeout.
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    /// Route requests through an HTTP or HTTPS proxy, e.g. `"http://proxy.example.com:8080"`.
+    /// This is useful in corporate environments where direct outbound connections aren't
+    /// permitted. Default is no proxy.
+    pub fn proxy(mut self, proxy: impl Into<String>) -> Self {
+        self.proxy = Some(proxy.into());
+        self
+    }
+
+    /// Set the policy for retrying failed requests. Default is [`RetryPolicy::default`]; pass
+    /// [`RetryPolicy::disabled`] to turn retries off entirely.
+    pub fn retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Apply the configured timeouts and proxy to an outgoing request. ureq 1.x has no
+    /// `Agent`-level equivalent of these (no `AgentBuilder`, no `Agent::proxy`); they're set per
+    /// `Request` instead, so every place that builds a `ureq::Request` from this config needs to
+    /// call this. That is only the request loop in [`UreqClient`]; oauth2 token requests go
+    /// through that same loop.
+    fn apply_to(&self, req: &mut ureq::Request) {
+        if let Some(timeout) = self.connect_timeout {
+            req.timeout_connect(timeout.as_millis() as u64);
+        }
+
+        if let Some(timeout) = self.read_timeout {
+            req.timeout_read(timeout.as_millis() as u64);
+        }
+
+        if let Some(proxy) = &self.proxy {
+            match ureq::Proxy::new(proxy) {
+                Ok(proxy) => { req.set_proxy(proxy); }
+                Err(e) => error!("invalid proxy {:?}: {}", proxy, e),
+            }
+        }
+    }
+}
+
+/// How the default client retries requests that fail with a transient, retryable HTTP status
+/// (429 Too Many Requests, or a 5xx server error).
+///
+/// On each retry, if the response carried a `Retry-After` header, the client waits for the time
+/// it specifies (parsed as either a number of seconds or an HTTP-date). Otherwise it waits
+/// `base_delay * 2^(attempt - 1)`, with full jitter (a random duration between zero and that
+/// value), capped at `max_delay`.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 4,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy that never retries; the first non-OK response is returned to the caller as-is.
+    pub fn disabled() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Self::default()
+        }
+    }
+
+    /// Set the maximum number of attempts to make for a single logical request, including the
+    /// first one. Default is 4.
+    pub fn max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// Set the base delay used for the exponential backoff when the server doesn't tell us how
+    /// long to wait. Default is 500ms.
+    pub fn base_delay(mut self, base_delay: Duration) -> Self {
+        self.base_delay = base_delay;
+        self
+    }
+
+    /// Set the maximum exponential-backoff delay to wait between attempts. Default is 30 seconds.
+    /// A wait given by the server in a `Retry-After` header is not capped by this.
+    pub fn max_delay(mut self, max_delay: Duration) -> Self {
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// Compute how long to sleep before the next attempt (1-indexed), honoring a `Retry-After`
+    /// header if the server sent one.
+    fn delay_for(&self, attempt: u32, retry_after: Option<&str>) -> Duration {
+        if let Some(retry_after) = retry_after.and_then(parse_retry_after) {
+            return retry_after;
+        }
+
+        let backoff = self.base_delay.saturating_mul(1u32 << attempt.saturating_sub(1).min(16));
+        let capped = backoff.min(self.max_delay);
+        capped.mul_f64(rand::random::<f64>())
+    }
+}
+
+/// Parse a `Retry-After` header value, either as an integer number of seconds or an HTTP-date.
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    if let Ok(secs) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+
+    let when = httpdate::parse_http_date(value.trim()).ok()?;
+    let now = std::time::SystemTime::now();
+    when.duration_since(now).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_retry_after_accepts_integer_seconds() {
+        assert_eq!(parse_retry_after("120"), Some(Duration::from_secs(120)));
+    }
+
+    #[test]
+    fn parse_retry_after_accepts_http_date() {
+        let future = std::time::SystemTime::now() + Duration::from_secs(3600);
+        let formatted = httpdate::fmt_http_date(future);
+        let parsed = parse_retry_after(&formatted).expect("should parse HTTP-date");
+        // Allow a little slack for HTTP-date's one-second resolution and the time elapsed since
+        // `future` was computed above.
+        assert!(parsed.as_secs() > 3590 && parsed.as_secs() <= 3600);
+    }
+
+    #[test]
+    fn parse_retry_after_rejects_garbage() {
+        assert_eq!(parse_retry_after("not a valid value"), None);
+    }
+
+    #[test]
+    fn retry_policy_delay_for_honors_retry_after_over_backoff() {
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.delay_for(1, Some("42")), Duration::from_secs(42));
+    }
+
+    #[test]
+    fn retry_policy_delay_for_caps_at_max_delay() {
+        let policy = RetryPolicy::default().max_delay(Duration::from_secs(5));
+        // With enough attempts, the uncapped exponential backoff would vastly exceed max_delay;
+        // the jittered result must never exceed it.
+        for attempt in 1..20 {
+            assert!(policy.delay_for(attempt, None) <= Duration::from_secs(5));
+        }
+    }
+}
+
 macro_rules! forward_request {
     ($self:ident, $inner:expr, $token:expr, $team_select:expr, $namespace_id: expr) => {
         fn request(
@@ -36,19 +244,87 @@ macro_rules! forward_request {
     }
 }
 
+/// The client ID and secret (or lack thereof, if using PKCE) needed to refresh an access token.
+#[derive(Debug, Clone)]
+struct RefreshConfig {
+    client_id: String,
+    client_secret: Option<String>,
+    refresh_token: String,
+}
+
+/// The currently-cached access token and, if we know how to get a new one, what we'll need to do
+/// that.
+#[derive(Debug)]
+struct TokenState {
+    access_token: String,
+    expires_at: Option<std::time::Instant>,
+    refresh: Option<RefreshConfig>,
+}
+
+impl TokenState {
+    fn is_expired(&self) -> bool {
+        matches!(self.expires_at, Some(expires_at) if std::time::Instant::now() >= expires_at)
+    }
+}
+
 /// Default HTTP client using User authorization.
+///
+/// If constructed with [`UserAuthDefaultClient::new_with_refresh`], this client holds a refresh
+/// token alongside the access token, and transparently refreshes the access token (proactively
+/// once it's past its known expiry, or reactively on a `401` response) rather than making callers
+/// do that dance themselves.
 pub struct UserAuthDefaultClient {
     inner: UreqClient,
-    token: String,
+    token: std::sync::Mutex<TokenState>,
     namespace_id: Option<NamespaceId>,
 }
 
 impl UserAuthDefaultClient {
-    /// Create a new client using the given OAuth2 token.
+    /// Create a new client using the given OAuth2 token. The token is used as-is for the lifetime
+    /// of the client; if it expires, requests will start failing. Use
+    /// [`UserAuthDefaultClient::new_with_refresh`] instead if you have a refresh token.
     pub fn new(token: String) -> Self {
+        Self::new_with_config(token, ClientConfig::default())
+    }
+
+    /// Create a new client using the given OAuth2 token and HTTP client configuration.
+    pub fn new_with_config(token: String, config: ClientConfig) -> Self {
         Self {
-            inner: UreqClient::default(),
-            token,
+            inner: UreqClient::new(config),
+            token: std::sync::Mutex::new(TokenState {
+                access_token: token,
+                expires_at: None,
+                refresh: None,
+            }),
+            namespace_id: None,
+        }
+    }
+
+    /// Create a new client that can refresh its own access token, from the result of an OAuth2
+    /// token exchange that returned a refresh token (see
+    /// [`crate::oauth2::oauth2_token_from_authorization_code_pkce`] or
+    /// [`crate::oauth2::refresh_access_token`]).
+    ///
+    /// `client_secret` should be `Some` if the app was set up with a client secret, or `None` if
+    /// it was set up with PKCE instead; whichever it is, it must match what was used to obtain
+    /// `token` in the first place.
+    pub fn new_with_refresh(
+        client_id: String,
+        client_secret: Option<String>,
+        token: crate::oauth2::Oauth2AccessToken,
+        config: ClientConfig,
+    ) -> Self {
+        Self {
+            inner: UreqClient::new(config),
+            token: std::sync::Mutex::new(TokenState {
+                access_token: token.access_token,
+                expires_at: Some(std::time::Instant::now() + token.expires_in),
+                refresh: Some(RefreshConfig {
+                    client_id,
+                    client_secret,
+                    refresh_token: token.refresh_token,
+                }),
+            }),
             namespace_id: None,
         }
     }
@@ -57,10 +333,91 @@ impl UserAuthDefaultClient {
     pub fn namespace_id(&mut self, namespace_id: Option<NamespaceId>) {
         self.namespace_id = namespace_id;
     }
+
+    /// Refresh the cached access token in place. Returns the lock guard, now holding the new
+    /// access token (or unchanged, if there is no refresh token to use).
+    ///
+    /// Is called with the lock already held and keeps it for the whole refresh so that concurrent callers that raced to detect an
+    /// expired token don't each fire off their own refresh request; the loser(s) of the race just
+    /// see the winner's already-refreshed token once they get the lock.
+    fn refresh_locked<'a>(&self, mut state: std::sync::MutexGuard<'a, TokenState>)
+        -> crate::Result<std::sync::MutexGuard<'a, TokenState>>
+    {
+        let refresh = match &state.refresh {
+            Some(refresh) => refresh.clone(),
+            None => return Ok(state),
+        };
+
+        debug!("refreshing expired access token");
+        // Share `inner`'s agent (and so its configured timeouts, proxy, and retry policy) with the
+        // refresh request rather than going around it, so a user who set those up still gets them
+        // applied when we silently refresh their token on their behalf.
+        let refresh_client = NoauthDefaultClient { inner: self.inner.clone() };
+        let new_token = crate::oauth2::refresh_access_token(
+            refresh_client,
+            &refresh.client_id,
+            refresh.client_secret.as_deref(),
+            &refresh.refresh_token,
+        )?;
+
+        state.access_token = new_token.access_token;
+        state.expires_at = Some(std::time::Instant::now() + new_token.expires_in);
+        state.refresh = Some(RefreshConfig { refresh_token: new_token.refresh_token, ..refresh });
+
+        Ok(state)
+    }
 }
 
 impl HttpClient for UserAuthDefaultClient {
-    forward_request! { self, self.inner, Some(&self.token), None, self.namespace_id.as_ref() }
+    fn request(
+        &self,
+        endpoint: Endpoint,
+        style: Style,
+        function: &str,
+        params: String,
+        params_type: ParamsType,
+        body: Option<&[u8]>,
+        range_start: Option<u64>,
+        range_end: Option<u64>,
+    ) -> crate::Result<HttpRequestResultRaw> {
+        let mut state = self.token.lock().unwrap();
+        if state.is_expired() {
+            state = self.refresh_locked(state)?;
+        }
+        let token = state.access_token.clone();
+        drop(state);
+
+        let result = self.inner.request(endpoint, style, function, params.clone(), params_type,
+            body, range_start, range_end, Some(&token), None, self.namespace_id.as_ref());
+
+        if !matches!(result, Err(Error::UnexpectedHttpError { code: 401, .. })) {
+            return result;
+        }
+
+        // Only replay on a 401 if we actually have a refresh token to try, and only actually hit
+        // the refresh endpoint if `token` (what the failed request used) is still the token on
+        // file: if it isn't, some other racing request already refreshed it out from under us
+        // while we weren't holding the lock, and we can just reuse what they got instead of
+        // refreshing a second time.
+        let mut state = self.token.lock().unwrap();
+        if state.refresh.is_none() {
+            return result;
+        }
+        if state.access_token == token {
+            state = self.refresh_locked(state)?;
+        }
+        let new_token = state.access_token.clone();
+        drop(state);
+
+        if new_token == token {
+            // Refreshing didn't actually give us a different token; replaying would just 401
+            // again, so return the original error instead of looping.
+            return result;
+        }
+
+        self.inner.request(endpoint, style, function, params, params_type, body, range_start,
+            range_end, Some(&new_token), None, self.namespace_id.as_ref())
+    }
 }
 
 impl UserAuthClient for UserAuthDefaultClient {}
@@ -75,8 +432,14 @@ pub struct TeamAuthDefaultClient {
 impl TeamAuthDefaultClient {
     /// Create a new client using the given OAuth2 token, with no user/admin context selected.
     pub fn new(token: String) -> Self {
+        Self::new_with_config(token, ClientConfig::default())
+    }
+
+    /// Create a new client using the given OAuth2 token and HTTP client configuration, with no
+    /// user/admin context selected.
+    pub fn new_with_config(token: String, config: ClientConfig) -> Self {
         Self {
-            inner: UreqClient::default(),
+            inner: UreqClient::new(config),
             token,
             team_select: None,
         }
@@ -100,16 +463,46 @@ pub struct NoauthDefaultClient {
     inner: UreqClient,
 }
 
+impl NoauthDefaultClient {
+    /// Create a new client using the given HTTP client configuration.
+    pub fn new_with_config(config: ClientConfig) -> Self {
+        Self {
+            inner: UreqClient::new(config),
+        }
+    }
+}
+
 impl HttpClient for NoauthDefaultClient {
     forward_request! { self, self.inner, None, None, None }
 }
 
 impl NoauthClient for NoauthDefaultClient {}
 
-#[derive(Debug, Default)]
-struct UreqClient {}
+#[derive(Debug, Clone)]
+struct UreqClient {
+    // Just a plain `ureq::agent()`: in ureq 1.x the `Agent` has no configurable timeouts or
+    // proxy, only connection reuse, so the per-request settings in `config` get applied to each
+    // `ureq::Request` instead (see `ClientConfig::apply_to`). `Agent` is Arc-backed internally, so
+    // cloning `UreqClient` (e.g. to share it with an oauth2 token refresh) is cheap and keeps the
+    // same pooled connections.
+    agent: ureq::Agent,
+    config: ClientConfig,
+}
+
+impl Default for UreqClient {
+    fn default() -> Self {
+        Self::new(ClientConfig::default())
+    }
+}
 
 impl UreqClient {
+    fn new(config: ClientConfig) -> Self {
+        Self {
+            agent: ureq::agent(),
+            config,
+        }
+    }
+
     #[allow(clippy::too_many_arguments)]
     fn request(
         &self,
@@ -129,65 +522,91 @@ impl UreqClient {
         let url = endpoint.url().to_owned() + function;
         debug!("request for {:?}", url);
 
-        let mut req = ureq::post(&url);
-        req.set("User-Agent", USER_AGENT);
+        // Rpc and Download requests are idempotent (the latter never even has an outgoing body),
+        // so replaying the whole request on a transient error is safe. Upload isn't: the server
+        // may have already committed the write (or appended the chunk to an upload session)
+        // before a 5xx got sent back, and blindly resending would duplicate the file or desync
+        // the session offset. So only those two styles get retried.
+        let max_attempts = if style == Style::Upload { 1 } else { self.config.retry_policy.max_attempts };
 
-        if let Some(token) = token {
-            req.set("Authorization", &format!("Bearer {}", token));
-        }
+        let mut attempt = 0u32;
+        loop {
+            attempt += 1;
+
+            let mut req = self.agent.post(&url);
+            req.set("User-Agent", USER_AGENT);
+            self.config.apply_to(&mut req);
 
-        if let Some(team_select) = team_select {
-            match team_select {
-                TeamSelect::User(id) => { req.set("Dropbox-API-Select-User", id); }
-                TeamSelect::Admin(id) => { req.set("Dropbox-API-Select-Admin", id); }
+            if let Some(token) = token {
+                req.set("Authorization", &format!("Bearer {}", token));
             }
-        }
 
-        if let Some(namespace_id) = namespace_id {
-            let namespace_tag = format!(r#"{{".tag": "namespace_id", "namespace_id": "{}"}}"#, namespace_id);
-            req.set("Dropbox-API-Path-Root", &namespace_tag);
-        }
+            if let Some(team_select) = team_select {
+                match team_select {
+                    TeamSelect::User(id) => { req.set("Dropbox-API-Select-User", id); }
+                    TeamSelect::Admin(id) => { req.set("Dropbox-API-Select-Admin", id); }
+                }
+            }
 
-        match (range_start, range_end) {
-            (Some(start), Some(end)) => { req.set("Range", &format!("bytes={}-{}", start, end)); }
-            (Some(start), None) => { req.set("Range", &format!("bytes={}-", start)); }
-            (None, Some(end)) => { req.set("Range", &format!("bytes=-{}", end)); }
-            (None, None) => (),
-        }
+            if let Some(namespace_id) = namespace_id {
+                let namespace_tag = format!(r#"{{".tag": "namespace_id", "namespace_id": "{}"}}"#, namespace_id);
+                req.set("Dropbox-API-Path-Root", &namespace_tag);
+            }
 
-        // If the params are totally empty, don't send any arg header or body.
-        let resp = if params.is_empty() {
-            req.call()
-        } else {
-            match style {
-                Style::Rpc => {
-                    // Send params in the body.
-                    req.set("Content-Type", params_type.content_type());
-                    req.send_string(&params)
-                }
-                Style::Upload | Style::Download => {
-                    // Send params in a header.
-                    req.set("Dropbox-API-Arg", &params);
-                    if style == Style::Upload {
-                        req.set("Content-Type", "application/octet-stream");
-                        if let Some(body) = body {
-                            req.send_bytes(body)
+            match (range_start, range_end) {
+                (Some(start), Some(end)) => { req.set("Range", &format!("bytes={}-{}", start, end)); }
+                (Some(start), None) => { req.set("Range", &format!("bytes={}-", start)); }
+                (None, Some(end)) => { req.set("Range", &format!("bytes=-{}", end)); }
+                (None, None) => (),
+            }
+
+            // If the params are totally empty, don't send any arg header or body.
+            let resp = if params.is_empty() {
+                req.call()
+            } else {
+                match style {
+                    Style::Rpc => {
+                        // Send params in the body.
+                        req.set("Content-Type", params_type.content_type());
+                        req.send_string(&params)
+                    }
+                    Style::Upload | Style::Download => {
+                        // Send params in a header.
+                        req.set("Dropbox-API-Arg", &params);
+                        if style == Style::Upload {
+                            req.set("Content-Type", "application/octet-stream");
+                            if let Some(body) = body {
+                                req.send_bytes(body)
+                            } else {
+                                req.send_bytes(&[])
+                            }
                         } else {
-                            req.send_bytes(&[])
+                            assert!(body.is_none(), "body can only be set for Style::Upload request");
+                            req.call()
                         }
-                    } else {
-                        assert!(body.is_none(), "body can only be set for Style::Upload request");
-                        req.call()
                     }
                 }
+            };
+
+            if let Some(ref err) = resp.synthetic_error() {
+                error!("request failed: {}", err);
+                return Err(RequestError { inner: resp }.into());
+            }
+
+            let status = resp.status();
+            if (status == 429 || (500..600).contains(&status)) && attempt < max_attempts {
+                let delay = self.config.retry_policy.delay_for(attempt, resp.header("Retry-After"));
+                warn!("request got status {}, retrying in {:?} (attempt {}/{})",
+                    status, delay, attempt, max_attempts);
+                std::thread::sleep(delay);
+                continue;
             }
-        };
 
-        if let Some(ref err) = resp.synthetic_error() {
-            error!("request failed: {}", err);
-            return Err(RequestError { inner: resp }.into());
+            return self.finish_response(style, resp);
         }
+    }
 
+    fn finish_response(&self, style: Style, resp: ureq::Response) -> crate::Result<HttpRequestResultRaw> {
         if !resp.ok() {
             let code = resp.status();
             let status = resp.status_text().to_owned();
@@ -0,0 +1,240 @@
+// Copyright (c) 2020 Dropbox, Inc.
+
+//! Higher-level helpers built on top of the generated API bindings.
+//!
+//! Currently this just contains [`upload_file`], a resumable-upload helper built on the upload
+//! session endpoints (`upload_session_start` / `upload_session_append_v2` /
+//! `upload_session_finish`), for files too large (or too important) to trust to a single
+//! `files::upload` call. See [`files::download`] and the demo's resuming download loop for the
+//! download-side equivalent of this kind of robustness.
+
+use crate::files;
+use crate::UserAuthClient;
+
+use std::io::Read;
+
+/// Dropbox documents 150 MiB as the largest request the upload endpoints will accept, so chunks
+/// must be no bigger than this.
+pub const MAX_CHUNK_SIZE: u64 = 150 * 1024 * 1024;
+
+/// The default chunk size used by [`upload_file`] if none is given: a few MiB, well under
+/// [`MAX_CHUNK_SIZE`], so a dropped connection partway through a chunk doesn't lose much work.
+pub const DEFAULT_CHUNK_SIZE: u64 = 8 * 1024 * 1024;
+
+/// Everything that can go wrong partway through [`upload_file`]: reading the source, or one of the
+/// three upload-session calls failing with an API-level error (as opposed to a request-level
+/// [`crate::Error`], which is returned directly rather than wrapped in this type).
+#[derive(thiserror::Error, Debug)]
+pub enum UploadError {
+    /// Failed to read the next chunk from the source.
+    #[error("error reading upload source: {0}")]
+    Read(#[from] std::io::Error),
+
+    /// `upload_session_start` returned an API error.
+    #[error("error starting upload session: {0}")]
+    Start(files::UploadSessionStartError),
+
+    /// `upload_session_append_v2` returned an API error (other than a resumable incorrect-offset,
+    /// which is handled internally).
+    #[error("error appending to upload session: {0}")]
+    Append(files::UploadSessionLookupError),
+
+    /// `upload_session_finish` returned an API error.
+    #[error("error finishing upload session: {0}")]
+    Finish(files::UploadSessionFinishError),
+}
+
+/// Upload the contents of `source` to `commit.path`, using a Dropbox upload session so the data
+/// can be streamed up in fixed-size chunks rather than having to fit in memory (or a single HTTP
+/// request) all at once.
+///
+/// `chunk_size` controls how much is buffered and sent per request; it's clamped to
+/// [`MAX_CHUNK_SIZE`]. Pass `None` to use [`DEFAULT_CHUNK_SIZE`].
+///
+/// `progress` is called after each chunk is successfully sent, with the total number of bytes
+/// uploaded so far, so callers can print something like the demo's download percentage.
+///
+/// If a chunk is rejected with an `incorrect_offset` error (e.g. because a previous attempt at
+/// appending it actually went through, despite the request appearing to fail), the correct offset
+/// reported by the server is used to resync and resend just the unsent tail of that chunk, rather
+/// than failing the whole upload.
+pub fn upload_file<T: UserAuthClient>(
+    client: &T,
+    mut source: impl Read,
+    commit: files::CommitInfo,
+    chunk_size: Option<u64>,
+    mut progress: impl FnMut(u64),
+) -> crate::Result<Result<files::FileMetadata, UploadError>> {
+    let chunk_size = chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE).min(MAX_CHUNK_SIZE) as usize;
+    let mut buf = vec![0u8; chunk_size];
+
+    let (n, eof) = match read_chunk(&mut source, &mut buf) {
+        Ok(result) => result,
+        Err(e) => return Ok(Err(e.into())),
+    };
+
+    let start_arg = files::UploadSessionStartArg::default().with_close(eof);
+    let session_id = match files::upload_session_start(client, &start_arg, Some(&buf[..n]))? {
+        Ok(result) => result.session_id,
+        Err(e) => return Ok(Err(UploadError::Start(e))),
+    };
+
+    let mut offset = n as u64;
+    progress(offset);
+
+    if eof {
+        return finish(client, session_id, offset, commit);
+    }
+
+    loop {
+        let chunk_start = offset;
+        let (n, eof) = match read_chunk(&mut source, &mut buf) {
+            Ok(result) => result,
+            Err(e) => return Ok(Err(e.into())),
+        };
+
+        if eof {
+            let result = finish_with_resync(client, session_id, chunk_start, &buf[..n], commit)?;
+            if result.is_ok() {
+                progress(chunk_start + n as u64);
+            }
+            return Ok(result);
+        }
+
+        match append_chunk(client, &session_id, chunk_start, &buf[..n])? {
+            Ok(()) => {
+                offset = chunk_start + n as u64;
+                progress(offset);
+            }
+            Err(e) => return Ok(Err(UploadError::Append(e))),
+        }
+    }
+}
+
+/// Append one chunk, resyncing and resending the unsent tail if the server reports we're not at
+/// the offset we thought we were.
+fn append_chunk<T: UserAuthClient>(
+    client: &T,
+    session_id: &str,
+    chunk_start: u64,
+    chunk: &[u8],
+) -> crate::Result<Result<(), files::UploadSessionLookupError>> {
+    let mut start_in_chunk = 0usize;
+    loop {
+        let cursor = files::UploadSessionCursor::new(
+            session_id.to_owned(), chunk_start + start_in_chunk as u64);
+        let arg = files::UploadSessionAppendArg::new(cursor);
+
+        match files::upload_session_append_v2(client, &arg, Some(&chunk[start_in_chunk..]))? {
+            Ok(()) => return Ok(Ok(())),
+            Err(files::UploadSessionLookupError::IncorrectOffset(offset_error)) => {
+                let correct_offset = offset_error.correct_offset;
+                if correct_offset < chunk_start || correct_offset > chunk_start + chunk.len() as u64 {
+                    // The server's idea of the offset isn't even within the chunk we just tried
+                    // to send; we can't resync from data we no longer have buffered.
+                    return Ok(Err(files::UploadSessionLookupError::IncorrectOffset(offset_error)));
+                }
+                warn!("upload session {} got incorrect_offset, resyncing to {} and resending",
+                    session_id, correct_offset);
+                start_in_chunk = (correct_offset - chunk_start) as usize;
+            }
+            Err(e) => return Ok(Err(e)),
+        }
+    }
+}
+
+/// Finish a session whose only chunk was already uploaded (and closed) by `upload_session_start`
+/// itself. There's no buffered data left to resend here, so unlike [`finish_with_resync`], an
+/// `incorrect_offset` error can't be recovered from: it means the server's idea of what got
+/// uploaded doesn't match ours, and we have nothing left to resync with.
+fn finish<T: UserAuthClient>(
+    client: &T,
+    session_id: String,
+    offset: u64,
+    commit: files::CommitInfo,
+) -> crate::Result<Result<files::FileMetadata, UploadError>> {
+    let cursor = files::UploadSessionCursor::new(session_id, offset);
+    let finish_arg = files::UploadSessionFinishArg::new(cursor, commit);
+    match files::upload_session_finish(client, &finish_arg, None)? {
+        Ok(metadata) => Ok(Ok(metadata)),
+        Err(e) => Ok(Err(UploadError::Finish(e))),
+    }
+}
+
+/// Finish a session by sending its final chunk, resyncing and resending the unsent tail (like
+/// [`append_chunk`]) if the server reports we're not at the offset we thought we were.
+fn finish_with_resync<T: UserAuthClient>(
+    client: &T,
+    session_id: String,
+    chunk_start: u64,
+    chunk: &[u8],
+    commit: files::CommitInfo,
+) -> crate::Result<Result<files::FileMetadata, UploadError>> {
+    let mut start_in_chunk = 0usize;
+    loop {
+        let cursor = files::UploadSessionCursor::new(
+            session_id.clone(), chunk_start + start_in_chunk as u64);
+        let finish_arg = files::UploadSessionFinishArg::new(cursor, commit.clone());
+
+        match files::upload_session_finish(client, &finish_arg, Some(&chunk[start_in_chunk..]))? {
+            Ok(metadata) => return Ok(Ok(metadata)),
+            Err(files::UploadSessionFinishError::LookupFailed(
+                files::UploadSessionLookupError::IncorrectOffset(offset_error))) => {
+                let correct_offset = offset_error.correct_offset;
+                if correct_offset < chunk_start || correct_offset > chunk_start + chunk.len() as u64 {
+                    // The server's idea of the offset isn't even within the chunk we just tried
+                    // to send; we can't resync from data we no longer have buffered.
+                    return Ok(Err(UploadError::Finish(files::UploadSessionFinishError::LookupFailed(
+                        files::UploadSessionLookupError::IncorrectOffset(offset_error)))));
+                }
+                warn!("upload session {} got incorrect_offset finishing, resyncing to {} and resending",
+                    session_id, correct_offset);
+                start_in_chunk = (correct_offset - chunk_start) as usize;
+            }
+            Err(e) => return Ok(Err(UploadError::Finish(e))),
+        }
+    }
+}
+
+/// Read until `buf` is full or the source is exhausted, returning the number of bytes read and
+/// whether we hit EOF.
+fn read_chunk(source: &mut impl Read, buf: &mut [u8]) -> std::io::Result<(usize, bool)> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match source.read(&mut buf[filled..]) {
+            Ok(0) => return Ok((filled, true)),
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok((filled, false))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_chunk_reports_eof_on_short_read() {
+        let mut source = &b"hello"[..];
+        let mut buf = [0u8; 8];
+        assert_eq!(read_chunk(&mut source, &mut buf).unwrap(), (5, true));
+        assert_eq!(&buf[..5], b"hello");
+    }
+
+    #[test]
+    fn read_chunk_fills_buffer_without_eof_on_exact_multiple() {
+        let mut source = &b"hello"[..];
+        let mut buf = [0u8; 5];
+        assert_eq!(read_chunk(&mut source, &mut buf).unwrap(), (5, false));
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[test]
+    fn read_chunk_reports_eof_with_nothing_left() {
+        let mut source = &b""[..];
+        let mut buf = [0u8; 4];
+        assert_eq!(read_chunk(&mut source, &mut buf).unwrap(), (0, true));
+    }
+}
@@ -0,0 +1,342 @@
+// Copyright (c) 2020 Dropbox, Inc.
+
+//! Tools for completing an OAuth2 flow to get an API access token for a user, either the legacy
+//! long-lived way, or using refresh tokens (optionally with PKCE for apps that can't hold a
+//! client secret).
+//!
+//! See <https://www.dropbox.com/developers/reference/oauth-guide> for a full description of the
+//! flows these functions implement.
+
+use std::fmt::Write as _;
+use std::time::Duration;
+
+use rand::Rng;
+use sha2::Digest;
+
+use crate::client_trait::{Endpoint, NoauthClient, ParamsType, Style};
+
+const AUTHORIZE_URL: &str = "https://www.dropbox.com/oauth2/authorize";
+
+/// Which OAuth2 flow to direct the user through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Oauth2Type {
+    /// The user is shown a code to paste back into your program, which your program then
+    /// exchanges for a token by calling the token endpoint itself. Use this with a client secret
+    /// (see [`oauth2_token_from_authorization_code`]), or with PKCE instead of a client secret
+    /// (see [`Oauth2AuthorizeUrlBuilder::pkce`] and
+    /// [`oauth2_token_from_authorization_code_pkce`]).
+    AuthorizationCode,
+
+    /// The token is returned directly in the redirect URL fragment, with no further request
+    /// needed. Only suitable for apps that can't make a second, server-side request at all; it
+    /// cannot produce a refresh token.
+    ImplicitGrant,
+}
+
+/// Builds the URL to send the user to in order to authorize your app.
+pub struct Oauth2AuthorizeUrlBuilder<'a> {
+    client_id: &'a str,
+    oauth2_type: Oauth2Type,
+    redirect_uri: Option<&'a str>,
+    state: Option<&'a str>,
+    force_reapprove: bool,
+    token_access_type_offline: bool,
+    code_challenge: Option<String>,
+}
+
+impl<'a> Oauth2AuthorizeUrlBuilder<'a> {
+    /// Make a new builder for the given app key (client ID) and flow type.
+    pub fn new(client_id: &'a str, oauth2_type: Oauth2Type) -> Self {
+        Self {
+            client_id,
+            oauth2_type,
+            redirect_uri: None,
+            state: None,
+            force_reapprove: false,
+            token_access_type_offline: false,
+            code_challenge: None,
+        }
+    }
+
+    /// Set the URI to redirect the user's browser to after they approve (or deny) your app. If
+    /// not given, the user is shown a code to copy and paste back into your app instead.
+    pub fn redirect_uri(mut self, redirect_uri: &'a str) -> Self {
+        self.redirect_uri = Some(redirect_uri);
+        self
+    }
+
+    /// Set an opaque value to round-trip through the redirect, to protect against CSRF.
+    pub fn state(mut self, state: &'a str) -> Self {
+        self.state = Some(state);
+        self
+    }
+
+    /// Force the user to re-approve the app even if they've already done so previously.
+    pub fn force_reapprove(mut self, force_reapprove: bool) -> Self {
+        self.force_reapprove = force_reapprove;
+        self
+    }
+
+    /// Request a long-lived refresh token in addition to the short-lived access token, so that
+    /// [`refresh_access_token`] can later be used to get a new access token without bothering the
+    /// user again. Only meaningful with [`Oauth2Type::AuthorizationCode`].
+    pub fn token_access_type_offline(mut self, offline: bool) -> Self {
+        self.token_access_type_offline = offline;
+        self
+    }
+
+    /// Use PKCE instead of a client secret to protect the later token exchange. This is the right
+    /// choice for installed apps and CLI tools, which can't safely embed a client secret. Hang
+    /// onto `verifier`; you'll need to pass it to
+    /// [`oauth2_token_from_authorization_code_pkce`] once the user comes back with their
+    /// authorization code. Only meaningful with [`Oauth2Type::AuthorizationCode`]. This also
+    /// turns on [`Oauth2AuthorizeUrlBuilder::token_access_type_offline`], so that the exchange
+    /// returns a refresh token.
+    pub fn pkce(mut self, verifier: &PkceCodeVerifier) -> Self {
+        self.code_challenge = Some(verifier.code_challenge());
+        self.token_access_type_offline = true;
+        self
+    }
+
+    /// Build the authorize URL.
+    pub fn build(self) -> String {
+        let mut url = format!(
+            "{}?response_type={}&client_id={}",
+            AUTHORIZE_URL,
+            match self.oauth2_type {
+                Oauth2Type::AuthorizationCode => "code",
+                Oauth2Type::ImplicitGrant => "token",
+            },
+            urlencode(self.client_id),
+        );
+
+        if let Some(redirect_uri) = self.redirect_uri {
+            write!(url, "&redirect_uri={}", urlencode(redirect_uri)).unwrap();
+        }
+        if let Some(state) = self.state {
+            write!(url, "&state={}", urlencode(state)).unwrap();
+        }
+        if self.force_reapprove {
+            url.push_str("&force_reapprove=true");
+        }
+        if self.token_access_type_offline {
+            url.push_str("&token_access_type=offline");
+        }
+        if let Some(code_challenge) = &self.code_challenge {
+            write!(url, "&code_challenge={}&code_challenge_method=S256", code_challenge).unwrap();
+        }
+
+        url
+    }
+}
+
+/// A PKCE code verifier: a high-entropy secret kept on the client, later presented alongside the
+/// authorization code to prove the token request comes from the same client that started the
+/// flow. See [RFC 7636](https://tools.ietf.org/html/rfc7636).
+#[derive(Clone)]
+pub struct PkceCodeVerifier(String);
+
+impl PkceCodeVerifier {
+    /// Generate a new, random code verifier: 128 characters (the upper end of the 43-128
+    /// character range the spec allows) drawn from the unreserved base64url alphabet plus `-._~`.
+    pub fn new() -> Self {
+        const CHARS: &[u8] =
+            b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-._~";
+        let mut rng = rand::thread_rng();
+        let verifier = (0..128)
+            .map(|_| CHARS[rng.gen_range(0..CHARS.len())] as char)
+            .collect();
+        Self(verifier)
+    }
+
+    /// The raw verifier string. Keep this around (e.g. in session state) between building the
+    /// authorize URL and completing the token exchange; it is never sent to Dropbox until then.
+    pub fn secret(&self) -> &str {
+        &self.0
+    }
+
+    fn code_challenge(&self) -> String {
+        let digest = sha2::Sha256::digest(self.0.as_bytes());
+        base64::encode_config(digest, base64::URL_SAFE_NO_PAD)
+    }
+}
+
+impl Default for PkceCodeVerifier {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// An access token obtained from a flow that also returns a refresh token, i.e. one that used
+/// [`Oauth2AuthorizeUrlBuilder::token_access_type_offline`] or
+/// [`Oauth2AuthorizeUrlBuilder::pkce`].
+#[derive(Debug, Clone)]
+pub struct Oauth2AccessToken {
+    /// The short-lived access token to put in the `Authorization: Bearer` header.
+    pub access_token: String,
+
+    /// How long `access_token` is valid for, from the moment this struct was created.
+    pub expires_in: Duration,
+
+    /// A long-lived token that can be exchanged for a new access token via
+    /// [`refresh_access_token`] once this one expires.
+    pub refresh_token: String,
+}
+
+/// Given an authorization code obtained by the user going through the URL from
+/// [`Oauth2AuthorizeUrlBuilder`] with [`Oauth2Type::AuthorizationCode`], request an access token.
+///
+/// `client` is used only to reach the token endpoint; an unauthenticated client such as
+/// [`crate::default_client::NoauthDefaultClient`] is all that's needed, and using it (rather than
+/// calling the endpoint directly) means the request honors whatever timeouts or proxy the caller
+/// configured on it.
+///
+/// This is the legacy flow: it returns only the access token. If the authorize URL used
+/// [`Oauth2AuthorizeUrlBuilder::token_access_type_offline`], the refresh token and expiry in the
+/// response are not returned by this function. To get an [`Oauth2AccessToken`] with a refresh
+/// token, use [`Oauth2AuthorizeUrlBuilder::pkce`] with
+/// [`oauth2_token_from_authorization_code_pkce`], and later [`refresh_access_token`].
+pub fn oauth2_token_from_authorization_code<T: NoauthClient>(
+    client: T,
+    client_id: &str,
+    client_secret: &str,
+    auth_code: &str,
+    redirect_uri: Option<&str>,
+) -> crate::Result<String> {
+    let mut params = vec![
+        ("code", auth_code),
+        ("grant_type", "authorization_code"),
+        ("client_id", client_id),
+        ("client_secret", client_secret),
+    ];
+    if let Some(redirect_uri) = redirect_uri {
+        params.push(("redirect_uri", redirect_uri));
+    }
+
+    let json = call_token_endpoint(&client, &params)?;
+    Ok(json["access_token"].as_str()
+        .ok_or(crate::Error::UnexpectedResponse("missing access_token in token response"))?
+        .to_owned())
+}
+
+/// Given an authorization code obtained by the user going through the URL from
+/// [`Oauth2AuthorizeUrlBuilder::pkce`], exchange it (along with the matching
+/// [`PkceCodeVerifier`]) for an access token, refresh token, and expiry, without needing a client
+/// secret. This is the flow to use for installed apps and CLI tools.
+///
+/// `client` is used only to reach the token endpoint; see [`oauth2_token_from_authorization_code`]
+/// for why it's taken at all.
+pub fn oauth2_token_from_authorization_code_pkce<T: NoauthClient>(
+    client: T,
+    client_id: &str,
+    auth_code: &str,
+    pkce_verifier: &PkceCodeVerifier,
+    redirect_uri: Option<&str>,
+) -> crate::Result<Oauth2AccessToken> {
+    let mut params = vec![
+        ("code", auth_code),
+        ("grant_type", "authorization_code"),
+        ("client_id", client_id),
+        ("code_verifier", pkce_verifier.secret()),
+    ];
+    if let Some(redirect_uri) = redirect_uri {
+        params.push(("redirect_uri", redirect_uri));
+    }
+
+    let json = call_token_endpoint(&client, &params)?;
+    parse_offline_token_response(&json)
+}
+
+/// Exchange a refresh token (obtained alongside an earlier access token; see
+/// [`Oauth2AccessToken::refresh_token`]) for a new access token, without bothering the user.
+///
+/// `client` is used only to reach the token endpoint; see [`oauth2_token_from_authorization_code`]
+/// for why it's taken at all. `client_secret` should be `Some` if the app was set up with a client
+/// secret (the `token_access_type=offline` flow), or `None` if it was set up with PKCE instead.
+pub fn refresh_access_token<T: NoauthClient>(
+    client: T,
+    client_id: &str,
+    client_secret: Option<&str>,
+    refresh_token: &str,
+) -> crate::Result<Oauth2AccessToken> {
+    let mut params = vec![
+        ("refresh_token", refresh_token),
+        ("grant_type", "refresh_token"),
+        ("client_id", client_id),
+    ];
+    if let Some(client_secret) = client_secret {
+        params.push(("client_secret", client_secret));
+    }
+
+    let json = call_token_endpoint(&client, &params)?;
+    parse_offline_token_response(&json)
+}
+
+fn parse_offline_token_response(json: &serde_json::Value) -> crate::Result<Oauth2AccessToken> {
+    let access_token = json["access_token"].as_str()
+        .ok_or(crate::Error::UnexpectedResponse("missing access_token in token response"))?
+        .to_owned();
+    let expires_in = json["expires_in"].as_u64()
+        .ok_or(crate::Error::UnexpectedResponse("missing expires_in in token response"))?;
+    let refresh_token = json["refresh_token"].as_str()
+        .ok_or(crate::Error::UnexpectedResponse("missing refresh_token in token response"))?
+        .to_owned();
+
+    Ok(Oauth2AccessToken {
+        access_token,
+        expires_in: Duration::from_secs(expires_in),
+        refresh_token,
+    })
+}
+
+fn call_token_endpoint<T: NoauthClient>(
+    client: &T,
+    params: &[(&str, &str)],
+) -> crate::Result<serde_json::Value> {
+    let body = serde_json::Value::Object(
+        params.iter().map(|(k, v)| (k.to_string(), serde_json::Value::String(v.to_string())))
+            .collect());
+
+    let result = client.request(
+        Endpoint::Api, Style::Rpc, "oauth2/token", body.to_string(), ParamsType::Json,
+        None, None, None)?;
+
+    Ok(serde_json::from_str(&result.result_json)?)
+}
+
+fn urlencode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for byte in s.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                out.push(byte as char);
+            }
+            _ => {
+                write!(out, "%{:02X}", byte).unwrap();
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pkce_code_challenge_matches_rfc_7636_test_vector() {
+        // From https://tools.ietf.org/html/rfc7636#appendix-B.
+        let verifier = PkceCodeVerifier("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk".to_owned());
+        assert_eq!(verifier.code_challenge(), "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM");
+    }
+
+    #[test]
+    fn urlencode_leaves_unreserved_characters_alone() {
+        assert_eq!(urlencode("abcXYZ019-._~"), "abcXYZ019-._~");
+    }
+
+    #[test]
+    fn urlencode_percent_encodes_everything_else() {
+        assert_eq!(urlencode("a b+c/d"), "a%20b%2Bc%2Fd");
+    }
+}